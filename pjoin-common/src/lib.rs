@@ -0,0 +1,178 @@
+// Copyright 2022 Nathan (Blaise) Bruer.  All rights reserved.
+
+// Write-path helpers shared by `pjoin` and `s3pjoin`: vectored/coalesced
+// writes to a file or stdout, and the optional io_uring backend.
+
+use std::io::{IoSlice, Write};
+
+// Maximum number of iovecs the kernel will accept in a single vmsplice/writev call.
+pub const IOV_MAX: usize = 1024;
+
+// Below this total size, many small queued chunks are copied into a single
+// buffer before writing instead of being sent as a batch of iovecs, since the
+// per-iovec bookkeeping dominates for a trickle of small data.
+pub const SMALL_CHUNK_COALESCE_THRESHOLD: usize = 32 * 1024; // 32kb
+
+pub fn write_vectored_loop<T, F>(chunks: &[T], mut write_iovs: F)
+where
+    T: AsRef<[u8]>,
+    F: FnMut(&[IoSlice]) -> usize,
+{
+    let mut chunk_idx = 0;
+    let mut chunk_offset = 0;
+    while chunk_idx < chunks.len() {
+        let end_idx = std::cmp::min(chunk_idx + IOV_MAX, chunks.len());
+        let iovs: Vec<IoSlice> = chunks[chunk_idx..end_idx]
+            .iter()
+            .enumerate()
+            .map(|(i, chunk)| {
+                let bytes = chunk.as_ref();
+                if i == 0 {
+                    IoSlice::new(&bytes[chunk_offset..])
+                } else {
+                    IoSlice::new(bytes)
+                }
+            })
+            .collect();
+        let mut written = write_iovs(&iovs);
+        while written > 0 {
+            let remaining = chunks[chunk_idx].as_ref().len() - chunk_offset;
+            if written < remaining {
+                chunk_offset += written;
+                written = 0;
+            } else {
+                written -= remaining;
+                chunk_idx += 1;
+                chunk_offset = 0;
+            }
+        }
+    }
+}
+
+// Fallback used when the `io-uring` feature is disabled or the target isn't Linux.
+pub fn write_file_chunks_blocking<T: AsRef<[u8]>>(file: &mut std::fs::File, chunks: &[T]) -> usize {
+    let total_size: usize = chunks.iter().map(|chunk| chunk.as_ref().len()).sum();
+    if chunks.len() > 1 && total_size < SMALL_CHUNK_COALESCE_THRESHOLD {
+        let mut buffer = Vec::with_capacity(total_size);
+        for chunk in chunks {
+            buffer.extend_from_slice(chunk.as_ref());
+        }
+        file.write_all(&buffer).unwrap();
+    } else {
+        write_vectored_loop(chunks, |iovs| file.write_vectored(iovs).unwrap());
+    }
+    total_size
+}
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub mod io_uring_writer {
+    use std::collections::HashMap;
+    use std::os::unix::io::AsRawFd;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use io_uring::{opcode, types, IoUring};
+
+    // Number of writes the ring is allowed to have in flight at once; this
+    // is also the backpressure point `write_chunks` blocks on.
+    const RING_DEPTH: u32 = 128;
+
+    pub struct IoUringFileWriter {
+        ring: IoUring,
+        file: std::fs::File,
+        // Buffers owned by in-flight SQEs, keyed by `user_data` so a
+        // completion can be matched back to its buffer (and the offset it
+        // was submitted at) regardless of the order CQEs arrive in.
+        in_flight: HashMap<u64, (u64, Vec<u8>)>,
+        next_user_data: u64,
+        // File isn't opened with O_APPEND, so each write needs its own
+        // explicit, monotonically-increasing offset.
+        next_offset: u64,
+        bytes_in_flight: Arc<AtomicUsize>,
+    }
+
+    impl IoUringFileWriter {
+        pub fn new(file: std::fs::File, bytes_in_flight: Arc<AtomicUsize>) -> Self {
+            let ring = IoUring::new(RING_DEPTH).expect("failed to create io_uring instance");
+            Self {
+                ring,
+                file,
+                in_flight: HashMap::new(),
+                next_user_data: 0,
+                next_offset: 0,
+                bytes_in_flight,
+            }
+        }
+
+        pub fn write_chunks<T: AsRef<[u8]>>(&mut self, chunks: &[T]) {
+            for chunk in chunks {
+                self.reap_until_slot_free();
+                let offset = self.next_offset;
+                let buffer = chunk.as_ref().to_vec();
+                self.next_offset += buffer.len() as u64;
+                self.enqueue(buffer, offset);
+            }
+            self.ring.submit().unwrap();
+        }
+
+        fn enqueue(&mut self, buffer: Vec<u8>, offset: u64) {
+            let user_data = self.next_user_data;
+            self.next_user_data += 1;
+            let write_e = opcode::Write::new(
+                types::Fd(self.file.as_raw_fd()),
+                buffer.as_ptr(),
+                buffer.len() as u32,
+            )
+            .offset(offset)
+            .build()
+            .user_data(user_data);
+            self.in_flight.insert(user_data, (offset, buffer));
+            unsafe {
+                self.ring
+                    .submission()
+                    .push(&write_e)
+                    .expect("io_uring submission queue is full");
+            }
+        }
+
+        fn reap_until_slot_free(&mut self) {
+            if self.in_flight.len() < RING_DEPTH as usize {
+                return;
+            }
+            self.reap_one();
+        }
+
+        fn reap_one(&mut self) {
+            // Also flushes any SQEs queued by `enqueue` that haven't been
+            // submitted to the kernel yet.
+            self.ring.submit_and_wait(1).unwrap();
+            let cqe = self
+                .ring
+                .completion()
+                .next()
+                .expect("io_uring completion queue unexpectedly empty");
+            let result = cqe.result();
+            assert!(result >= 0, "io_uring write failed: {}", result);
+            let written = result as usize;
+            let (offset, buffer) = self
+                .in_flight
+                .remove(&cqe.user_data())
+                .expect("completion for unknown write");
+            if written < buffer.len() {
+                // Short write (signal interruption, ENOSPC, etc. are all
+                // real possibilities for a plain write()) — resubmit the
+                // unwritten tail at its own offset instead of dropping it.
+                let remaining_offset = offset + written as u64;
+                let remaining = buffer[written..].to_vec();
+                self.enqueue(remaining, remaining_offset);
+            }
+            self.bytes_in_flight.fetch_sub(written, Ordering::AcqRel);
+        }
+
+        pub fn flush(&mut self) {
+            while !self.in_flight.is_empty() {
+                self.reap_one();
+            }
+        }
+    }
+}
@@ -2,24 +2,42 @@
 
 use std::fs::OpenOptions;
 use std::io::{stdin, stdout, BufRead, BufReader, Read};
-use std::io::{IoSlice, Write};
-use std::os::unix::prelude::AsRawFd;
-use std::process::{Command, Stdio};
-use std::sync::mpsc::{sync_channel, Receiver};
+use std::io::IoSlice;
+use std::os::unix::prelude::{AsRawFd, FromRawFd};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
 use std::thread::{spawn, JoinHandle};
+use std::time::Duration;
 
 use async_std::task;
 use clap::Parser;
 use futures::future::ready;
 use futures::{stream, StreamExt};
 use nix::fcntl::{vmsplice, SpliceFFlags};
+use nix::pty::{openpty, OpenptyResult, Winsize};
+use nix::unistd::dup;
+use pjoin_common::SMALL_CHUNK_COALESCE_THRESHOLD;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use pjoin_common::io_uring_writer::IoUringFileWriter;
+use pjoin_common::{write_file_chunks_blocking, write_vectored_loop};
 use shlex;
 
+// TIOCSWINSZ isn't wrapped by nix, so generate a thin ioctl binding for it.
+nix::ioctl_write_ptr_bad!(set_winsize, nix::libc::TIOCSWINSZ, Winsize);
+
 const DEFAULT_CONCURRENT_LIMIT: usize = 16;
-const DEFAULT_BUFFER_SIZE: usize = 1 << 30; // 1Gb
+const DEFAULT_BACKPRESSURE_LIMIT: usize = 64 * 1024 * 1024; // 64Mb
+const DEFAULT_PTY_ROWS: u16 = 24;
+const DEFAULT_PTY_COLS: u16 = 80;
 
 const CHUNK_BUFFER_SIZE: usize = 2 * 1024 * 1024; // 2mb
 
+// How long a reader thread sleeps between checks of `bytes_in_flight` while
+// it is over the backpressure limit.
+const BACKPRESSURE_POLL_INTERVAL: Duration = Duration::from_micros(100);
+
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
@@ -27,104 +45,243 @@ struct Args {
     #[clap(short, long, value_parser, default_value_t = DEFAULT_CONCURRENT_LIMIT)]
     parallel_count: usize,
 
-    /// Size in bytes of the stdout buffer for reach command
-    #[clap(short, long, value_parser, default_value_t = DEFAULT_BUFFER_SIZE)]
-    buffer_size: usize,
+    /// Total bytes of not-yet-written output allowed to be queued across all
+    /// commands at once before reader threads block. Bounds memory use
+    /// regardless of how many bytes each command produces per read or how
+    /// many commands run in parallel
+    #[clap(short, long, value_parser, default_value_t = DEFAULT_BACKPRESSURE_LIMIT)]
+    backpressure_limit: usize,
+
+    /// Run each command attached to a pseudo-terminal instead of a plain pipe,
+    /// so tools that check isatty() on their stdout/stderr enable color,
+    /// progress bars, or line-buffering as they would run interactively
+    #[clap(long)]
+    pty: bool,
+
+    /// Initial number of rows to report to commands via TIOCSWINSZ when
+    /// `--pty` is set
+    #[clap(long, value_parser, default_value_t = DEFAULT_PTY_ROWS, requires = "pty")]
+    pty_rows: u16,
+
+    /// Initial number of columns to report to commands via TIOCSWINSZ when
+    /// `--pty` is set
+    #[clap(long, value_parser, default_value_t = DEFAULT_PTY_COLS, requires = "pty")]
+    pty_cols: u16,
 
     /// Path to write file. Prints to stdout if not set. Using a file can be faster than stdout
     #[clap(value_parser)]
     output_file: Option<String>,
 }
 
-fn collect_and_write_data<S, F>(mut process_stream: S, mut write_fn: F)
-where
-    S: futures::Stream<Item = (Receiver<Vec<u8>>, JoinHandle<()>)> + std::marker::Unpin,
-    F: FnMut(Vec<u8>),
+fn collect_and_write_data<S, T, F>(
+    mut process_stream: S,
+    bytes_in_flight: Arc<AtomicUsize>,
+    mut write_fn: F,
+) where
+    S: futures::Stream<Item = (Receiver<T>, JoinHandle<()>)> + std::marker::Unpin,
+    T: AsRef<[u8]>,
+    // Returns the number of bytes to drop from `bytes_in_flight`; a
+    // synchronous writer returns the full amount, io_uring returns 0 and
+    // accounts for its buffers itself once they're reaped.
+    F: FnMut(&[T]) -> usize,
 {
     task::block_on(async {
         while let Some((rx, thread_join)) = process_stream.next().await {
-            while let Ok(chunk) = rx.recv() {
-                (write_fn)(chunk);
+            while let Ok(first_chunk) = rx.recv() {
+                // Drain whatever else is already queued so we can issue one
+                // vectored write instead of one syscall per chunk.
+                let mut chunks = vec![first_chunk];
+                while let Ok(chunk) = rx.try_recv() {
+                    chunks.push(chunk);
+                }
+                let completed_size = (write_fn)(&chunks);
+                bytes_in_flight.fetch_sub(completed_size, Ordering::AcqRel);
             }
             thread_join.join().unwrap();
         }
     })
 }
 
+fn pump_child_output<R: Read>(
+    mut reader: R,
+    mut child_process: Child,
+    tx: Sender<Vec<u8>>,
+    bytes_in_flight: Arc<AtomicUsize>,
+    backpressure_limit: usize,
+    treat_eio_as_eof: bool,
+) {
+    loop {
+        let mut buffer = Vec::with_capacity(CHUNK_BUFFER_SIZE);
+        unsafe {
+            buffer.set_len(CHUNK_BUFFER_SIZE);
+        }
+        let mut bytes_read = 0;
+        while bytes_read <= CHUNK_BUFFER_SIZE {
+            let len = match reader.read(&mut buffer[bytes_read..CHUNK_BUFFER_SIZE]) {
+                Ok(len) => len,
+                // A pty master reports its slave closing as EIO, not a 0-byte read.
+                Err(e) if treat_eio_as_eof && e.raw_os_error() == Some(nix::libc::EIO) => 0,
+                Err(e) => panic!("{}", e),
+            };
+            if len == 0 {
+                break;
+            }
+            bytes_read += len;
+        }
+        buffer.truncate(bytes_read);
+        if bytes_read == 0 {
+            let exit_code = child_process.wait().unwrap();
+            assert!(exit_code.success());
+            break;
+        }
+        while bytes_in_flight.load(Ordering::Acquire) >= backpressure_limit {
+            std::thread::sleep(BACKPRESSURE_POLL_INTERVAL);
+        }
+        bytes_in_flight.fetch_add(buffer.len(), Ordering::AcqRel);
+        tx.send(buffer).unwrap();
+    }
+}
+
 fn main() {
     let args = Args::parse();
+    let backpressure_limit = args.backpressure_limit;
+    let pty_enabled = args.pty;
+    let pty_rows = args.pty_rows;
+    let pty_cols = args.pty_cols;
+
+    // Tracks total bytes queued but not yet written across all inputs, so
+    // memory use stays bounded regardless of chunk sizes or parallel_count.
+    let bytes_in_flight = Arc::new(AtomicUsize::new(0));
 
     let stdin = stdin().lock();
     let stdin_buf = BufReader::new(stdin);
     let process_stream = stream::iter(stdin_buf.lines())
-        .map(move |maybe_command| {
-            let full_command = maybe_command.unwrap();
-            let split_commands = shlex::split(&full_command).unwrap();
-            let mut command_builder = Command::new(&split_commands[0]);
-            command_builder
-                .args(&split_commands[1..])
-                .stdin(Stdio::null())
-                .stdout(Stdio::piped());
-            let mut child_process = match command_builder.spawn() {
-                Ok(child_process) => child_process,
-                Err(e) => {
-                    println!("Could not run command '{}'", full_command);
-                    panic!("{}", e);
-                }
-            };
-            let (tx, rx) = sync_channel(args.buffer_size / CHUNK_BUFFER_SIZE);
-
-            let thread_join = spawn(move || {
-                let mut stdout = child_process.stdout.take().unwrap();
-                loop {
-                    let mut buffer = Vec::with_capacity(CHUNK_BUFFER_SIZE);
-                    unsafe {
-                        buffer.set_len(CHUNK_BUFFER_SIZE);
-                    }
-                    let mut bytes_read = 0;
-                    while bytes_read <= CHUNK_BUFFER_SIZE {
-                        let len = stdout
-                            .read(&mut buffer[bytes_read..CHUNK_BUFFER_SIZE])
-                            .unwrap();
-                        if len == 0 {
-                            break;
+        .map({
+            let bytes_in_flight = bytes_in_flight.clone();
+            move |maybe_command| {
+                let full_command = maybe_command.unwrap();
+                let split_commands = shlex::split(&full_command).unwrap();
+                let mut command_builder = Command::new(&split_commands[0]);
+                command_builder
+                    .args(&split_commands[1..])
+                    .stdin(Stdio::null());
+
+                let (tx, rx) = channel();
+                let bytes_in_flight = bytes_in_flight.clone();
+
+                let thread_join = if pty_enabled {
+                    let OpenptyResult { master, slave } =
+                        openpty(None, None).expect("failed to allocate a pty");
+                    let winsize = Winsize {
+                        ws_row: pty_rows,
+                        ws_col: pty_cols,
+                        ws_xpixel: 0,
+                        ws_ypixel: 0,
+                    };
+                    unsafe { set_winsize(slave, &winsize) }.expect("failed to set pty window size");
+
+                    let slave_stderr = dup(slave).unwrap();
+                    command_builder
+                        .stdout(unsafe { Stdio::from_raw_fd(slave) })
+                        .stderr(unsafe { Stdio::from_raw_fd(slave_stderr) });
+                    let child_process = match command_builder.spawn() {
+                        Ok(child_process) => child_process,
+                        Err(e) => {
+                            println!("Could not run command '{}'", full_command);
+                            panic!("{}", e);
                         }
-                        bytes_read += len;
-                    }
-                    buffer.truncate(bytes_read);
-                    if bytes_read == 0 {
-                        let exit_code = child_process.wait().unwrap();
-                        assert!(exit_code.success());
-                        break;
-                    }
-                    tx.send(buffer).unwrap();
-                }
-            });
-            ready((rx, thread_join))
+                    };
+                    let master_file = unsafe { std::fs::File::from_raw_fd(master) };
+                    spawn(move || {
+                        pump_child_output(
+                            master_file,
+                            child_process,
+                            tx,
+                            bytes_in_flight,
+                            backpressure_limit,
+                            true,
+                        )
+                    })
+                } else {
+                    command_builder.stdout(Stdio::piped());
+                    let mut child_process = match command_builder.spawn() {
+                        Ok(child_process) => child_process,
+                        Err(e) => {
+                            println!("Could not run command '{}'", full_command);
+                            panic!("{}", e);
+                        }
+                    };
+                    let stdout = child_process.stdout.take().unwrap();
+                    spawn(move || {
+                        pump_child_output(
+                            stdout,
+                            child_process,
+                            tx,
+                            bytes_in_flight,
+                            backpressure_limit,
+                            false,
+                        )
+                    })
+                };
+                ready((rx, thread_join))
+            }
         })
         .buffered(args.parallel_count);
 
     if let Some(output_file) = args.output_file {
-        let mut file = OpenOptions::new()
+        let file = OpenOptions::new()
             .write(true)
             .truncate(true)
             .create(true)
             .open(output_file)
             .unwrap();
-        collect_and_write_data(process_stream, move |chunk| file.write_all(&chunk).unwrap());
+
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        {
+            let mut writer = IoUringFileWriter::new(file, bytes_in_flight.clone());
+            collect_and_write_data(process_stream, bytes_in_flight, |chunks: &[Vec<u8>]| {
+                writer.write_chunks(chunks);
+                0
+            });
+            writer.flush();
+        }
+
+        #[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+        {
+            let mut file = file;
+            collect_and_write_data(process_stream, bytes_in_flight, |chunks: &[Vec<u8>]| {
+                write_file_chunks_blocking(&mut file, chunks)
+            });
+        }
     } else {
         let stdout = stdout().lock();
         // This is an optimization to make writing to a pipe significantly faster.
-        collect_and_write_data(process_stream, move |chunk| {
-            let chunk_size = chunk.len();
-            let mut bytes_written = 0;
-            while bytes_written < chunk_size {
-                let iov = [IoSlice::new(&chunk[bytes_written..])];
-                match vmsplice(stdout.as_raw_fd(), &iov, SpliceFFlags::SPLICE_F_GIFT) {
-                    Ok(sz) => bytes_written += sz,
-                    Err(e) => panic!("{}", e),
+        collect_and_write_data(process_stream, bytes_in_flight, move |chunks: &[Vec<u8>]| {
+            let total_size: usize = chunks.iter().map(|chunk| chunk.len()).sum();
+            if chunks.len() > 1 && total_size < SMALL_CHUNK_COALESCE_THRESHOLD {
+                // A trickle of small chunks: one copy into a contiguous
+                // buffer is cheaper than the per-iovec overhead below.
+                let mut buffer = Vec::with_capacity(total_size);
+                for chunk in chunks {
+                    buffer.extend_from_slice(chunk);
+                }
+                let mut bytes_written = 0;
+                while bytes_written < buffer.len() {
+                    let iov = [IoSlice::new(&buffer[bytes_written..])];
+                    match vmsplice(stdout.as_raw_fd(), &iov, SpliceFFlags::SPLICE_F_GIFT) {
+                        Ok(sz) => bytes_written += sz,
+                        Err(e) => panic!("{}", e),
+                    }
                 }
+            } else {
+                write_vectored_loop(chunks, |iovs| {
+                    match vmsplice(stdout.as_raw_fd(), iovs, SpliceFFlags::SPLICE_F_GIFT) {
+                        Ok(sz) => sz,
+                        Err(e) => panic!("{}", e),
+                    }
+                });
             }
+            total_size
         });
     }
 }
\ No newline at end of file
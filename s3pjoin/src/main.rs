@@ -2,24 +2,45 @@
 
 use std::fs::OpenOptions;
 use std::io::{stdin, stdout, BufRead, BufReader};
-use std::io::{IoSlice, Write};
+use std::io::IoSlice;
 use std::os::unix::prelude::AsRawFd;
-use std::sync::mpsc::{sync_channel, Receiver};
-use std::thread::{spawn, JoinHandle};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
 use aws_sdk_s3::model::RequestPayer;
+use aws_sdk_s3::Client;
 use async_std::task;
+use async_std::task::JoinHandle;
 use clap::Parser;
-use futures::future::ready;
-use futures::{stream, StreamExt};
+use futures::future::{join_all, ready};
+use futures::{stream, FutureExt, StreamExt};
 use bytes::Bytes;
 use nix::fcntl::{vmsplice, SpliceFFlags};
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use pjoin_common::io_uring_writer::IoUringFileWriter;
+use pjoin_common::{write_file_chunks_blocking, write_vectored_loop, SMALL_CHUNK_COALESCE_THRESHOLD};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
 
 const DEFAULT_CONCURRENT_LIMIT: usize = 16;
-const DEFAULT_BUFFER_SIZE: usize = 1 << 30; // 1Gb
+const DEFAULT_BACKPRESSURE_LIMIT: usize = 64 * 1024 * 1024; // 64Mb
+
+// How many requests (the first one included) `fetch_range` makes for a single
+// range, each resuming from its last received offset, before it gives up.
+const SPLIT_RANGE_RETRY_LIMIT: u32 = 5;
+
+// Bound on how many not-yet-consumed chunks a single input's channel holds.
+// `bytes_in_flight` is the real memory bound; this just keeps a stalled
+// writer from letting one fast input queue unbounded message counts.
+const CHANNEL_CAPACITY: usize = 16;
 
 const CHUNK_BUFFER_SIZE: usize = 2 * 1024 * 1024; // 2mb
 
+// How long a reader task sleeps between checks of `bytes_in_flight` while
+// it is over the backpressure limit.
+const BACKPRESSURE_POLL_INTERVAL: Duration = Duration::from_micros(100);
+
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
@@ -27,32 +48,236 @@ struct Args {
     #[clap(short, long, value_parser, default_value_t = DEFAULT_CONCURRENT_LIMIT)]
     parallel_count: usize,
 
-    /// Size in bytes of the stdout buffer for reach command
-    #[clap(short, long, value_parser, default_value_t = DEFAULT_BUFFER_SIZE)]
-    buffer_size: usize,
+    /// Total bytes of not-yet-written output allowed to be queued across all
+    /// objects at once before reader tasks wait. Bounds memory use
+    /// regardless of how large each fetched chunk is or how many objects are
+    /// fetched in parallel
+    #[clap(short, long, value_parser, default_value_t = DEFAULT_BACKPRESSURE_LIMIT)]
+    backpressure_limit: usize,
 
     /// Path to write file. Prints to stdout if not set. Using a file can be faster than stdout
     #[clap(value_parser)]
     output_file: Option<String>,
 }
 
-fn collect_and_write_data<S, F>(mut process_stream: S, mut write_fn: F)
-where
-    S: futures::Stream<Item = (Receiver<Bytes>, JoinHandle<()>)> + std::marker::Unpin,
-    F: FnMut(Bytes),
+fn collect_and_write_data<S, T, F>(
+    mut process_stream: S,
+    bytes_in_flight: Arc<AtomicUsize>,
+    mut write_fn: F,
+) where
+    S: futures::Stream<Item = (ReceiverStream<T>, JoinHandle<()>)> + std::marker::Unpin,
+    T: AsRef<[u8]>,
+    // Returns the number of bytes to drop from `bytes_in_flight`; a
+    // synchronous writer returns the full amount, io_uring returns 0 and
+    // accounts for its buffers itself once they're reaped.
+    F: FnMut(&[T]) -> usize,
 {
     task::block_on(async {
-        while let Some((rx, thread_join)) = process_stream.next().await {
-            while let Ok(chunk) = rx.recv() {
-                (write_fn)(chunk);
+        while let Some((mut rx, reader_task)) = process_stream.next().await {
+            while let Some(first_chunk) = rx.next().await {
+                // Drain whatever else is already available so we can issue
+                // one vectored write instead of one syscall per chunk.
+                let mut chunks = vec![first_chunk];
+                while let Some(Some(chunk)) = rx.next().now_or_never() {
+                    chunks.push(chunk);
+                }
+                let completed_size = (write_fn)(&chunks);
+                bytes_in_flight.fetch_sub(completed_size, Ordering::AcqRel);
             }
-            thread_join.join().unwrap();
+            reader_task.await;
         }
     })
 }
 
+fn parse_split_suffix(path: &str) -> (&str, Option<usize>) {
+    match path.rsplit_once("#split=") {
+        Some((base, count)) => (base, count.parse().ok()),
+        None => (path, None),
+    }
+}
+
+async fn fetch_range(
+    s3_client: &Client,
+    bucket: &str,
+    key: &str,
+    start: u64,
+    end: u64,
+    bytes_in_flight: &Arc<AtomicUsize>,
+    backpressure_limit: usize,
+) -> Vec<Bytes> {
+    use tokio_stream::StreamExt;
+
+    let range_len = end - start + 1;
+    let mut received: u64 = 0;
+    let mut chunks = Vec::new();
+    let mut attempt = 0;
+    while received < range_len {
+        attempt += 1;
+        if attempt > SPLIT_RANGE_RETRY_LIMIT {
+            panic!(
+                "giving up on bytes={}-{} of {}/{} after {} attempts",
+                start, end, bucket, key, SPLIT_RANGE_RETRY_LIMIT
+            );
+        }
+
+        let mut response = s3_client
+            .get_object()
+            .bucket(bucket)
+            .key(key)
+            .range(format!("bytes={}-{}", start + received, end))
+            .request_payer(RequestPayer::Requester)
+            .send()
+            .await
+            .unwrap();
+
+        // A transient stream error only loses us the rest of this range; the
+        // outer `while` retries from `received` instead of restarting it.
+        while let Ok(Some(bytes)) = response.body.try_next().await {
+            received += bytes.len() as u64;
+            while bytes_in_flight.load(Ordering::Acquire) >= backpressure_limit {
+                task::sleep(BACKPRESSURE_POLL_INTERVAL).await;
+            }
+            bytes_in_flight.fetch_add(bytes.len(), Ordering::AcqRel);
+            chunks.push(bytes);
+        }
+    }
+    chunks
+}
+
+async fn fetch_split_object(
+    s3_client: Client,
+    bucket: String,
+    key: String,
+    split_count: usize,
+    tx: mpsc::Sender<Bytes>,
+    bytes_in_flight: Arc<AtomicUsize>,
+    backpressure_limit: usize,
+) {
+    let head = s3_client
+        .head_object()
+        .bucket(&bucket)
+        .key(&key)
+        .request_payer(RequestPayer::Requester)
+        .send()
+        .await
+        .unwrap();
+    // A missing Content-Length means the HEAD response didn't tell us how
+    // large the object is, not that it's empty — don't fold the two cases
+    // together and silently emit zero bytes for it.
+    let content_length = head
+        .content_length()
+        .unwrap_or_else(|| panic!("HEAD response for {}/{} had no content_length", bucket, key))
+        .max(0) as u64;
+    if content_length == 0 {
+        return;
+    }
+
+    // Can't usefully split into more ranges than there are bytes.
+    let split_count = (split_count as u64).min(content_length);
+    let base_len = content_length / split_count;
+    let remainder = content_length % split_count;
+
+    let mut ranges = Vec::with_capacity(split_count as usize);
+    let mut start = 0;
+    for i in 0..split_count {
+        // Spread the remainder over the first few ranges so the split stays
+        // as even as possible.
+        let len = base_len + if i < remainder { 1 } else { 0 };
+        let end = start + len - 1;
+        ranges.push((start, end));
+        start = end + 1;
+    }
+
+    let range_results = join_all(ranges.into_iter().map(|(start, end)| {
+        fetch_range(
+            &s3_client,
+            &bucket,
+            &key,
+            start,
+            end,
+            &bytes_in_flight,
+            backpressure_limit,
+        )
+    }))
+    .await;
+
+    // Ranges can finish fetching out of order; forward them in range order
+    // so the output is a faithful concatenation regardless.
+    for chunks in range_results {
+        for bytes in chunks {
+            tx.send(bytes).await.unwrap();
+        }
+    }
+}
+
+async fn fetch_whole_object(
+    s3_client: Client,
+    bucket: String,
+    key: String,
+    tx: mpsc::Sender<Bytes>,
+    bytes_in_flight: Arc<AtomicUsize>,
+    backpressure_limit: usize,
+) {
+    use tokio_stream::StreamExt;
+
+    let mut response = s3_client
+        .get_object()
+        .bucket(&bucket)
+        .key(&key)
+        .request_payer(RequestPayer::Requester)
+        .send()
+        .await
+        .unwrap();
+    while let Some(bytes) = response.body.try_next().await.unwrap() {
+        while bytes_in_flight.load(Ordering::Acquire) >= backpressure_limit {
+            task::sleep(BACKPRESSURE_POLL_INTERVAL).await;
+        }
+        bytes_in_flight.fetch_add(bytes.len(), Ordering::AcqRel);
+        tx.send(bytes).await.unwrap();
+    }
+}
+
+fn spawn_object_reader(
+    s3_client: Client,
+    s3_path: &str,
+    bytes_in_flight: Arc<AtomicUsize>,
+    backpressure_limit: usize,
+) -> (ReceiverStream<Bytes>, JoinHandle<()>) {
+    let (s3_path, split_count) = parse_split_suffix(s3_path);
+    let (bucket, key) = s3_path.split_once('/').expect("Bucket was not present in s3 path");
+    let bucket = bucket.to_string();
+    let key = key.to_string();
+
+    let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+    let reader_task = task::spawn(async move {
+        match split_count.filter(|&n| n > 1) {
+            Some(split_count) => {
+                fetch_split_object(
+                    s3_client,
+                    bucket,
+                    key,
+                    split_count,
+                    tx,
+                    bytes_in_flight,
+                    backpressure_limit,
+                )
+                .await;
+            }
+            None => {
+                fetch_whole_object(s3_client, bucket, key, tx, bytes_in_flight, backpressure_limit).await;
+            }
+        }
+    });
+    (ReceiverStream::new(rx), reader_task)
+}
+
 fn main() {
     let args = Args::parse();
+    let backpressure_limit = args.backpressure_limit;
+
+    // Tracks total bytes queued but not yet written across all inputs, so
+    // memory use stays bounded regardless of chunk sizes or parallel_count.
+    let bytes_in_flight = Arc::new(AtomicUsize::new(0));
 
     let s3_client = task::block_on(async {
         let shared_config = aws_config::load_from_env().await;
@@ -62,78 +287,75 @@ fn main() {
     let stdin = stdin().lock();
     let stdin_buf = BufReader::new(stdin);
     let process_stream = stream::iter(stdin_buf.lines())
-        .map(move |maybe_s3_path| {
-            let s3_path = maybe_s3_path.unwrap();
-
-            let (bucket, key) = s3_path.split_once('/').expect("Bucket was not present in s3 path");
-
-            let get_object_builder = s3_client
-                .get_object()
-                .bucket(bucket)
-                .key(key)
-                .request_payer(RequestPayer::Requester);
-
-            let (tx, rx) = sync_channel(args.buffer_size / CHUNK_BUFFER_SIZE);
-
-            let thread_join = spawn(move || {
-                // let mut stdout = child_process.stdout.take().unwrap();
-                task::block_on(async move {
-                    use tokio_stream::StreamExt;
-
-                    let mut response = get_object_builder.send().await.unwrap();
-                    while let Some(bytes) = response.body.try_next().await.unwrap() {
-                        tx.send(bytes).unwrap();
-                    }
-                });
-                // loop {
-                //     let mut buffer = Vec::with_capacity(CHUNK_BUFFER_SIZE);
-                //     unsafe {
-                //         buffer.set_len(CHUNK_BUFFER_SIZE);
-                //     }
-                //     let mut bytes_read = 0;
-                //     while bytes_read <= CHUNK_BUFFER_SIZE {
-                //         let len = stdout
-                //             .read(&mut buffer[bytes_read..CHUNK_BUFFER_SIZE])
-                //             .unwrap();
-                //         if len == 0 {
-                //             break;
-                //         }
-                //         bytes_read += len;
-                //     }
-                //     buffer.truncate(bytes_read);
-                //     if bytes_read == 0 {
-                //         let exit_code = child_process.wait().unwrap();
-                //         assert!(exit_code.success());
-                //         break;
-                //     }
-                //     tx.send(buffer).unwrap();
-                // }
-            });
-            ready((rx, thread_join))
+        .map({
+            let bytes_in_flight = bytes_in_flight.clone();
+            let s3_client = s3_client.clone();
+            move |maybe_s3_path| {
+                let s3_path = maybe_s3_path.unwrap();
+                ready(spawn_object_reader(
+                    s3_client.clone(),
+                    &s3_path,
+                    bytes_in_flight.clone(),
+                    backpressure_limit,
+                ))
+            }
         })
         .buffered(args.parallel_count);
 
     if let Some(output_file) = args.output_file {
-        let mut file = OpenOptions::new()
+        let file = OpenOptions::new()
             .write(true)
             .truncate(true)
             .create(true)
             .open(output_file)
             .unwrap();
-        collect_and_write_data(process_stream, move |chunk| file.write_all(&chunk).unwrap());
+
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        {
+            let mut writer = IoUringFileWriter::new(file, bytes_in_flight.clone());
+            collect_and_write_data(process_stream, bytes_in_flight, |chunks: &[Bytes]| {
+                writer.write_chunks(chunks);
+                0
+            });
+            writer.flush();
+        }
+
+        #[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+        {
+            let mut file = file;
+            collect_and_write_data(process_stream, bytes_in_flight, |chunks: &[Bytes]| {
+                write_file_chunks_blocking(&mut file, chunks)
+            });
+        }
     } else {
         let stdout = stdout().lock();
         // This is an optimization to make writing to a pipe significantly faster.
-        collect_and_write_data(process_stream, move |chunk| {
-            let chunk_size = chunk.len();
-            let mut bytes_written = 0;
-            while bytes_written < chunk_size {
-                let iov = [IoSlice::new(&chunk[bytes_written..])];
-                match vmsplice(stdout.as_raw_fd(), &iov, SpliceFFlags::SPLICE_F_GIFT) {
-                    Ok(sz) => bytes_written += sz,
-                    Err(e) => panic!("{}", e),
+        collect_and_write_data(process_stream, bytes_in_flight, move |chunks: &[Bytes]| {
+            let total_size: usize = chunks.iter().map(|chunk| chunk.len()).sum();
+            if chunks.len() > 1 && total_size < SMALL_CHUNK_COALESCE_THRESHOLD {
+                // A trickle of small chunks: one copy into a contiguous
+                // buffer is cheaper than the per-iovec overhead below.
+                let mut buffer = Vec::with_capacity(total_size);
+                for chunk in chunks {
+                    buffer.extend_from_slice(chunk);
                 }
+                let mut bytes_written = 0;
+                while bytes_written < buffer.len() {
+                    let iov = [IoSlice::new(&buffer[bytes_written..])];
+                    match vmsplice(stdout.as_raw_fd(), &iov, SpliceFFlags::SPLICE_F_GIFT) {
+                        Ok(sz) => bytes_written += sz,
+                        Err(e) => panic!("{}", e),
+                    }
+                }
+            } else {
+                write_vectored_loop(chunks, |iovs| {
+                    match vmsplice(stdout.as_raw_fd(), iovs, SpliceFFlags::SPLICE_F_GIFT) {
+                        Ok(sz) => sz,
+                        Err(e) => panic!("{}", e),
+                    }
+                });
             }
+            total_size
         });
     }
 }